@@ -0,0 +1,72 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use typst::foundations::Bytes;
+use typst::text::{Font, FontBook, FontInfo};
+
+// Searches for fonts on the system and in specified directories/files.
+pub struct FontSearcher {
+    pub book: FontBook,
+    pub fonts: Vec<FontSlot>,
+}
+
+// Lazily loaded and cached so repeated glyph lookups don't re-read the file.
+pub struct FontSlot {
+    pub path: PathBuf,
+    pub index: u32,
+    font: OnceLock<Option<Font>>,
+}
+
+impl FontSlot {
+    fn new(path: PathBuf, index: u32) -> Self {
+        Self { path, index, font: OnceLock::new() }
+    }
+
+    pub fn get(&self) -> Option<Font> {
+        self.font.get_or_init(|| {
+            let data = fs::read(&self.path).ok()?;
+            Font::new(Bytes::new(data), self.index)
+        }).clone()
+    }
+}
+
+impl FontSearcher {
+    pub fn new() -> Self {
+        Self { book: FontBook::new(), fonts: vec![] }
+    }
+
+    pub fn search_system(&mut self) {
+        for family in [
+            "/usr/share/fonts",
+            "/usr/local/share/fonts",
+            "/Library/Fonts",
+            "/System/Library/Fonts",
+        ] {
+            let path = Path::new(family);
+            if path.is_dir() {
+                self.search_dir(path);
+            }
+        }
+    }
+
+    pub fn search_dir(&mut self, dir: &Path) {
+        let Ok(entries) = fs::read_dir(dir) else { return };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                self.search_dir(&path);
+            } else if path.is_file() {
+                self.search_file(&path);
+            }
+        }
+    }
+
+    pub fn search_file(&mut self, path: &Path) {
+        let Ok(data) = fs::read(path) else { return };
+        for (index, info) in FontInfo::iter(&data).enumerate() {
+            self.book.push(info);
+            self.fonts.push(FontSlot::new(path.to_owned(), index as u32));
+        }
+    }
+}
@@ -8,7 +8,8 @@ use typst::ecow::EcoVec;
 use typst::Library;
 // use typst::eval::{ Library, Datetime };
 use typst::diag::{ FileError, FileResult, SourceDiagnostic, Warned };
-use typst::visualize::{Luma, Rgb};
+use typst::visualize::{Cmyk, Luma, Rgb};
+use chrono::Datelike;
 use typst::syntax::{ FileId, Source, Span, VirtualPath };
 use typst::text::{ Font, FontBook };
 use typst::World;
@@ -23,6 +24,7 @@ use ext_php_rs::types::{Zval, ZendHashTable};
 mod fonts;
 use fonts::FontSearcher;
 use fonts::FontSlot;
+use typst::layout::PagedDocument;
 use typst_pdf::PdfOptions;
 
 // WORLD
@@ -32,6 +34,9 @@ struct PHPWorld {
     main: Source,
     book: LazyHash<FontBook>,
     fonts: Vec<FontSlot>,
+    sources: HashMap<FileId, Source>,
+    files: HashMap<FileId, Bytes>,
+    root: Option<String>,
 }
 
 impl PHPWorld {
@@ -52,13 +57,38 @@ impl PHPWorld {
 
         let file_id = FileId::new(None, VirtualPath::new("./::php_source::"));
 
+        let mut sources = HashMap::new();
+        let mut files = HashMap::new();
+        for (virtual_path, contents) in builder.files.to_owned().into_iter() {
+            let id = FileId::new(None, VirtualPath::new(&virtual_path));
+            if let Ok(text) = String::from_utf8(contents.clone()) {
+                sources.insert(id, Source::new(id, text));
+            }
+            files.insert(id, Bytes::new(contents));
+        }
+
         Self {
             library: LazyHash::new(make_library(builder)),
             main: Source::new(file_id, body.to_owned()),
             book: LazyHash::new(fontsearcher.book),
             fonts: fontsearcher.fonts,
+            sources,
+            files,
+            root: builder.root.to_owned(),
         }
     }
+
+    fn read_from_root(&self, id: FileId) -> FileResult<Vec<u8>> {
+        let vpath = id.vpath();
+        let relative = vpath.as_rootless_path();
+
+        if relative.is_absolute() || relative.components().any(|c| c == std::path::Component::ParentDir) {
+            return Err(FileError::AccessDenied);
+        }
+
+        let root = self.root.as_deref().map(Path::new).unwrap_or(Path::new("."));
+        read(&root.join(relative))
+    }
 }
 
 impl World for PHPWorld {
@@ -70,8 +100,18 @@ impl World for PHPWorld {
         self.main.id()
     }
 
-    fn source(&self, _id: FileId) -> FileResult<Source> {
-        Ok(self.main.clone())
+    fn source(&self, id: FileId) -> FileResult<Source> {
+        if id == self.main.id() {
+            return Ok(self.main.clone());
+        }
+
+        if let Some(source) = self.sources.get(&id) {
+            return Ok(source.clone());
+        }
+
+        let data = self.read_from_root(id)?;
+        let text = String::from_utf8(data).map_err(|_| FileError::InvalidUtf8)?;
+        Ok(Source::new(id, text))
     }
 
     fn book(&self) -> &LazyHash<FontBook> {
@@ -79,29 +119,23 @@ impl World for PHPWorld {
     }
 
     fn font(&self, id: usize) -> Option<Font> {
-        let slot = &self.fonts[id];
-        let data = read(&slot.path).unwrap();
-        let bytes : Bytes = Bytes::new(data);
-        Font::new(bytes, slot.index)
-    }
-
-    fn file(&self, path: FileId) -> FileResult<Bytes> {
-        // if path.components().any(|c| c.as_os_str() == "..") {
-        //     Err(FileError::AccessDenied)
-        // }
-        // else if !path.is_relative() {
-        //     Err(FileError::AccessDenied)
-        // }
-        // else {
-        
-            let data = read(path.vpath().as_rooted_path()).unwrap();
-            let bytes : Bytes = Bytes::new(data);
-            Ok(bytes)
-        // }
-    }
-
-    fn today(&self, _offset:Option<i64>) -> Option<Datetime> {
-        Some(Datetime::from_ymd(1970, 1, 1).unwrap())
+        self.fonts[id].get()
+    }
+
+    fn file(&self, id: FileId) -> FileResult<Bytes> {
+        if let Some(bytes) = self.files.get(&id) {
+            return Ok(bytes.clone());
+        }
+
+        Ok(Bytes::new(self.read_from_root(id)?))
+    }
+
+    fn today(&self, offset: Option<i64>) -> Option<Datetime> {
+        let now = match offset {
+            Some(hours) => (chrono::Utc::now() + chrono::Duration::hours(hours)).naive_utc(),
+            None => chrono::Local::now().naive_local(),
+        };
+        Datetime::from_ymd(now.year(), now.month().try_into().ok()?, now.day().try_into().ok()?)
     }
 }
 
@@ -186,20 +220,28 @@ fn zval_to_typst(value: &Zval) -> Value {
         DataType::Object(_) => {
             let obj = value.object().unwrap();
             match obj.get_class_name().unwrap_or(String::from("")).as_str() {
-                // "TypstCMYK" => Value::Color(Cmyk::new(
-                //     obj.get_property::<u8>("c").unwrap() as f32,
-                //     obj.get_property::<u8>("m").unwrap() as f32,
-                //     obj.get_property::<u8>("y").unwrap() as f32,
-                //     obj.get_property::<u8>("k").unwrap() as f32,
-                // ).into()),
+                "TypstCMYK" => Value::Color(Cmyk::new(
+                    obj.get_property::<u8>("c").unwrap() as f32 / 255.0,
+                    obj.get_property::<u8>("m").unwrap() as f32 / 255.0,
+                    obj.get_property::<u8>("y").unwrap() as f32 / 255.0,
+                    obj.get_property::<u8>("k").unwrap() as f32 / 255.0,
+                ).into()),
+                "TypstDate" => match Datetime::from_ymd(
+                    obj.get_property::<i32>("year").unwrap(),
+                    obj.get_property::<u8>("month").unwrap(),
+                    obj.get_property::<u8>("day").unwrap(),
+                ) {
+                    Some(date) => Value::Datetime(date),
+                    None => Value::None,
+                },
                 "TypstRGBA" => Value::Color(Rgb::new(
-                    obj.get_property::<u8>("r").unwrap() as f32,
-                    obj.get_property::<u8>("g").unwrap() as f32,
-                    obj.get_property::<u8>("b").unwrap() as f32,
-                    obj.get_property::<u8>("a").unwrap() as f32,
+                    obj.get_property::<u8>("r").unwrap() as f32 / 255.0,
+                    obj.get_property::<u8>("g").unwrap() as f32 / 255.0,
+                    obj.get_property::<u8>("b").unwrap() as f32 / 255.0,
+                    obj.get_property::<u8>("a").unwrap() as f32 / 255.0,
                 ).into()),
                 "TypstLuma" => Value::Color(Luma::new(
-                    obj.get_property::<u8>("luma").unwrap() as f32,
+                    obj.get_property::<u8>("luma").unwrap() as f32 / 255.0,
                     1.0,
                 ).into()),
                 _ => match obj.get_properties() {
@@ -272,57 +314,67 @@ fn csv_to_typst(csv: &String, delimiter: u8, use_headers: bool) -> Value {
 
 // DIAGNOSTICS
 
-fn get_error_message(_world: &dyn World, _body: &str, errors: &EcoVec<SourceDiagnostic>) -> String {
-    let mut full_message = String::from("");
-    let mut first = true;
-    for error in errors {
-        if first { first = false }
-        else { full_message.push_str("\n"); }
-
-        full_message.push_str(&error.message);
-
-        // let range = error.(world);
-        // let body_bytes = body.as_bytes();
-
-        // let mut line_number = 1;
-        // for b in body_bytes[0..range.start].iter() {
-        //     if *b == 0x0A {
-        //         line_number += 1
-        //     }
-        // }
-
-        // full_message.push_str(&format!("Typst error on line {}: ", line_number));
-        // full_message.push_str(&String::from(error.message.to_owned()));
-
-        // let mut start = range.start;
-        // let mut end = range.end;
-        // if start > 0 && body_bytes[start] == 0x0A {
-        //     start -= 1
-        // }
-        // while body_bytes[start] != 0x0A {
-        //     if start == 0 { break; }
-        //     start -= 1;
-        // }
-        // if start == 0x0A { start += 1 }
-        // if end < body_bytes.len() && body_bytes[end] == 0x0A {
-        //     end += 1;
-        // }
-        // while end < body_bytes.len() && body_bytes[end] != 0x0A {
-        //     end += 1;
-        // }
-        // if end == 0x0A { end -= 1 }
-
-
-        // match String::from_utf8(body_bytes[start..end].into()) {
-        //     Ok(code) => {
-        //         full_message.push_str("\n");
-        //         full_message.push_str(&code);
-
-        //     }
-        //     _ => {},
-        // }
-    }
-    return full_message;
+fn resolve_span(world: &dyn World, span: Span) -> Option<(Source, std::ops::Range<usize>)> {
+    let id = span.id()?;
+    let source = world.source(id).ok()?;
+    let range = source.range(span)?;
+    Some((source, range))
+}
+
+fn line_col(source: &Source, offset: usize) -> (usize, usize) {
+    let before = &source.text()[..offset];
+    let line = before.matches('\n').count() + 1;
+    let last_newline = before.rfind('\n').map_or(0, |i| i + 1);
+    let col = before[last_newline..].chars().count() + 1;
+    (line, col)
+}
+
+fn annotated_line(source: &Source, range: &std::ops::Range<usize>) -> String {
+    let text = source.text();
+    let bytes = text.as_bytes();
+
+    let mut start = range.start.min(bytes.len());
+    while start > 0 && bytes[start - 1] != b'\n' { start -= 1; }
+    let mut end = range.end.min(bytes.len());
+    while end < bytes.len() && bytes[end] != b'\n' { end += 1; }
+
+    let highlight_start = range.start.max(start);
+    let highlight_end = range.end.min(end).max(highlight_start);
+
+    let caret_start = text[start..highlight_start].chars().count();
+    let caret_len = text[highlight_start..highlight_end].chars().count().max(1);
+
+    format!("{}\n{}{}", &text[start..end], " ".repeat(caret_start), "^".repeat(caret_len))
+}
+
+fn format_diagnostic(world: &dyn World, diagnostic: &SourceDiagnostic) -> String {
+    let location = resolve_span(world, diagnostic.span).or_else(|| {
+        diagnostic.trace.iter().find_map(|point| resolve_span(world, point.span))
+    });
+
+    let label = match diagnostic.severity {
+        typst::diag::Severity::Error => "error",
+        typst::diag::Severity::Warning => "warning",
+    };
+
+    match location {
+        Some((source, range)) => {
+            let (line, col) = line_col(&source, range.start);
+            format!(
+                "{}: {} (line {}, col {})\n{}",
+                label,
+                diagnostic.message,
+                line,
+                col,
+                annotated_line(&source, &range),
+            )
+        }
+        None => format!("{}: {}", label, diagnostic.message),
+    }
+}
+
+fn get_error_message(world: &dyn World, errors: &EcoVec<SourceDiagnostic>) -> String {
+    errors.iter().map(|error| format_diagnostic(world, error)).collect::<Vec<_>>().join("\n")
 }
 
 
@@ -358,6 +410,16 @@ pub struct TypstLuma {
     pub luma: u8,
 }
 
+#[php_class]
+pub struct TypstDate {
+    #[prop]
+    pub year: i32,
+    #[prop]
+    pub month: u8,
+    #[prop]
+    pub day: u8,
+}
+
 
 
 #[php_class]
@@ -366,6 +428,34 @@ pub struct Typst {
     json: HashMap<String, String>,
     vars: HashMap<String, Value>,
     fonts: Vec<String>,
+    files: HashMap<String, Vec<u8>>,
+    root: Option<String>,
+    warnings: Vec<String>,
+}
+
+impl Typst {
+    fn compile_document(&mut self) -> PhpResult<(PHPWorld, PagedDocument)> {
+        let world = PHPWorld::new(self);
+
+        if !self.body.is_some() {
+            return Err(PhpException::default(String::from("No body for typst compiler")));
+        }
+
+        let Warned { output, warnings } = typst::compile::<PagedDocument>(&world);
+        self.warnings = warnings.iter().map(|warning| format_diagnostic(&world, warning)).collect();
+
+        match output {
+            Ok(document) => Ok((world, document)),
+            Err(errors) => {
+                println!("{:?}", errors);
+                Err(PhpException::new(
+                    get_error_message(&world, &errors),
+                    8,
+                    ext_php_rs::zend::ce::exception(),
+                ))
+            }
+        }
+    }
 }
 
 #[php_impl(rename_methods = "none")]
@@ -376,6 +466,9 @@ impl Typst {
             json: HashMap::new(),
             vars: HashMap::new(),
             fonts: vec![],
+            files: HashMap::new(),
+            root: None,
+            warnings: vec![],
         }
     }
 
@@ -383,6 +476,18 @@ impl Typst {
         self.body = Some(body);
     }
 
+    fn get_warnings(&self) -> Vec<String> {
+        self.warnings.to_owned()
+    }
+
+    fn add_file(&mut self, virtual_path: String, contents: Binary<u8>) {
+        self.files.insert(virtual_path, contents.to_vec());
+    }
+
+    fn set_root(&mut self, dir: String) {
+        self.root = Some(dir);
+    }
+
     fn json(&mut self, key: String, value: String) {
         self.json.insert(key, value);
     }
@@ -409,31 +514,14 @@ impl Typst {
     }
 
     fn compile(&mut self) -> PhpResult<Binary<u8>> {
-        let world = PHPWorld::new(self);
+        let (world, document) = self.compile_document()?;
 
-        if !self.body.is_some() {
-            return Err(PhpException::default(String::from("No body for typst compiler")));
-        }
-
-        let Warned { output, warnings } = typst::compile(&world);
-        match output {
-            Ok(document) => {
-                match typst_pdf::pdf(&document, &PdfOptions::default()) {
-                    Ok(buffer) => Ok(buffer.into_iter().collect::<Binary<_>>()),
-                    Err(errors) => {
-                        println!("{:?}", errors);
-                        Err(PhpException::new(
-                            get_error_message(&world, &self.body.as_ref().unwrap(), &warnings),
-                            8,
-                            ext_php_rs::zend::ce::exception(),
-                        ))
-                    }
-                }
-            }
+        match typst_pdf::pdf(&document, &PdfOptions::default()) {
+            Ok(buffer) => Ok(buffer.into_iter().collect::<Binary<_>>()),
             Err(errors) => {
                 println!("{:?}", errors);
                 Err(PhpException::new(
-                    get_error_message(&world, &self.body.as_ref().unwrap(), &warnings),
+                    get_error_message(&world, &errors),
                     8,
                     ext_php_rs::zend::ce::exception(),
                 ))
@@ -441,6 +529,28 @@ impl Typst {
         }
     }
 
+    fn render_svg(&mut self) -> PhpResult<Vec<String>> {
+        let (_world, document) = self.compile_document()?;
+
+        Ok(document.pages.iter().map(|page| typst_svg::svg(page)).collect())
+    }
+
+    fn render_png(&mut self, ppi: Option<f32>) -> PhpResult<Vec<Binary<u8>>> {
+        let (_world, document) = self.compile_document()?;
+        let pixel_per_pt = ppi.unwrap_or(144.0) / 72.0;
+
+        document.pages.iter().map(|page| {
+            let pixmap = typst_render::render(page, pixel_per_pt);
+            pixmap.encode_png()
+                .map(|bytes| bytes.into_iter().collect::<Binary<_>>())
+                .map_err(|err| PhpException::new(
+                    format!("Failed to encode page as PNG: {}", err),
+                    8,
+                    ext_php_rs::zend::ce::exception(),
+                ))
+        }).collect()
+    }
+
     fn cmyk(c: u8, m: u8, y: u8, k: u8) -> TypstCMYK {
         TypstCMYK { c, m, y, k }
     }
@@ -453,6 +563,10 @@ impl Typst {
         TypstLuma { luma }
     }
 
+    fn date(year: i32, month: u8, day: u8) -> TypstDate {
+        TypstDate { year, month, day }
+    }
+
     fn register_font(&mut self, path: String) -> PhpResult<()> {
         if !path.starts_with("./") {
             Err(PhpException::default(String::from("Path must be relative.")))